@@ -1,60 +1,243 @@
+use cargo::core::dependency::Kind as CargoDepKind;
+use cargo::core::resolver::Resolve;
 use cargo::core::shell::Shell;
-use cargo::core::{Dependency as CargoDependency, GitReference, Workspace};
+use cargo::core::{Dependency as CargoDependency, PackageId, Workspace};
 use cargo::util::config::Config;
 use failure::{format_err, Fallible};
 use itertools::Itertools as _;
+use miniserde::Deserialize;
+use std::collections::HashMap;
 use std::fs;
+use std::io::{BufRead, BufReader};
 use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
 use structopt::clap::AppSettings;
 use structopt::StructOpt;
 use strum::{EnumString, EnumVariantNames};
 
+// Package -> compiled artifact path, built from a single `cargo build
+// --message-format=json` run so the `--resolver build-plan` flow never has
+// to guess at `.d` file contents.
+//
+// Keyed by `"{name}:{version}"`, built the same way on both the artifact
+// side (parsed out of the JSON message's `package_id` field) and the lookup
+// side (`PackageId::name`/`PackageId::version`) — neither of cargo's own
+// `PackageId::to_string()` nor a `PackageIdSpec` round-trip of the message's
+// `package_id` agree with each other, so the key is derived independently
+// instead of relying on either Display impl.
+type ArtifactMap = HashMap<String, PathBuf>;
+
+fn package_key(name: impl std::fmt::Display, version: impl std::fmt::Display) -> String {
+    format!("{}:{}", name, version)
+}
+
+// `cargo build --message-format=json` reports a package as `"name version
+// (source)"` (the form cargo serializes a `PackageId` to, not its Display
+// text, which is `"name vversion"`), so
+// pull the name and version back out of that rather than trying to parse it
+// as anything more structured.
+fn parse_artifact_key(package_id: &str) -> Fallible<String> {
+    let mut fields = package_id.split_whitespace();
+    let name = fields.next().ok_or_else(|| {
+        format_err!(
+            "malformed package id in cargo build output: `{}`",
+            package_id
+        )
+    })?;
+    let version = fields.next().ok_or_else(|| {
+        format_err!(
+            "malformed package id in cargo build output: `{}`",
+            package_id
+        )
+    })?;
+
+    Ok(package_key(name, version))
+}
+
+#[derive(Deserialize)]
+struct BuildMessage {
+    reason: String,
+    package_id: Option<String>,
+    filenames: Option<Vec<String>>,
+}
+
+fn build_artifact_map(manifest_path: &Path) -> Fallible<ArtifactMap> {
+    let mut child = Command::new("cargo")
+        .arg("build")
+        .arg("--release")
+        .arg("--message-format=json")
+        .arg("--manifest-path")
+        .arg(manifest_path)
+        .stdout(Stdio::piped())
+        .spawn()?;
+
+    let reader = BufReader::new(
+        child
+            .stdout
+            .take()
+            .ok_or_else(|| failure::err_msg("failed to capture `cargo build` stdout"))?,
+    );
+
+    let mut artifacts = ArtifactMap::new();
+    for line in reader.lines() {
+        let line = line?;
+        let message: BuildMessage = miniserde::json::from_str(&line).map_err(|_| {
+            format_err!(
+                "failed to parse `cargo build --message-format=json` line: `{}`",
+                line
+            )
+        })?;
+
+        if message.reason != "compiler-artifact" {
+            continue;
+        }
+
+        let package_id = message.package_id.ok_or_else(|| {
+            failure::err_msg("`compiler-artifact` message is missing `package_id`")
+        })?;
+        let library_path = message
+            .filenames
+            .unwrap_or_default()
+            .into_iter()
+            .find(|path| path.ends_with(".rlib") || path.ends_with(".so"));
+
+        if let Some(library_path) = library_path {
+            artifacts.insert(
+                parse_artifact_key(&package_id)?,
+                PathBuf::from(library_path),
+            );
+        }
+    }
+
+    let status = child.wait()?;
+    if !status.success() {
+        return Err(failure::err_msg(
+            "`cargo build --release --message-format=json` failed",
+        ));
+    }
+
+    Ok(artifacts)
+}
+
 struct Dependency {
     crate_name: String,
     library_path: PathBuf,
 }
 
 impl Dependency {
-    fn parse_git(package_name: String, git_ref: &GitReference) -> Fallible<Locator> {
-        match git_ref {
-            GitReference::Rev(revision) => Ok(Locator::Git {
-                package_name,
-                revision: revision.clone(),
-            }),
-            GitReference::Tag(_) => panic!("Tagged git source is not supported."),
-            GitReference::Branch(_) => panic!("Branch git source is not supported."),
-        }
+    fn load_resolve(ws: &Workspace) -> Fallible<Resolve> {
+        cargo::ops::load_pkg_lockfile(ws)?.ok_or_else(|| {
+            failure::err_msg("Cargo.lock not found; run `cargo build` first to generate one")
+        })
     }
 
-    fn parse_normal(package_name: String, version_req: String) -> Fallible<Locator> {
-        if !version_req.starts_with('=') {
-            return Err(failure::err_msg(
-                "use exact match version requirement: `= *.*.*`",
-            ));
-        }
+    fn parse_git(
+        package_id: PackageId,
+        package_name: String,
+        name_in_toml: String,
+    ) -> Fallible<Locator> {
+        let revision = package_id.source_id().precise().ok_or_else(|| {
+            format_err!(
+                "`{}` is a git dependency but has no locked revision in Cargo.lock",
+                package_name
+            )
+        })?;
+
+        Ok(Locator::Git {
+            package_id,
+            package_name,
+            name_in_toml,
+            revision: revision.to_string(),
+        })
+    }
 
-        let version = version_req[1..].trim().to_string();
+    fn parse_normal(
+        package_id: PackageId,
+        package_name: String,
+        name_in_toml: String,
+    ) -> Fallible<Locator> {
+        let version = package_id.version().to_string();
 
         Ok(Locator::Version {
+            package_id,
             package_name,
+            name_in_toml,
             version,
         })
     }
 
-    pub fn parse(deps_path: &Path, dep: &CargoDependency) -> Fallible<Dependency> {
+    fn parse_path(
+        dep: &CargoDependency,
+        package_id: PackageId,
+        package_name: String,
+        name_in_toml: String,
+    ) -> Fallible<Locator> {
+        let source_path = dep.source_id().url().to_file_path().map_err(|_| {
+            format_err!(
+                "`{}` is a path dependency but its source is not a local path",
+                package_name
+            )
+        })?;
+
+        Ok(Locator::Path {
+            package_id,
+            package_name,
+            name_in_toml,
+            source_path,
+        })
+    }
+
+    // Looks up the `PackageId` that `dep` resolves to for `current_id` in the
+    // dependency graph, rather than querying the lockfile by bare package
+    // name: a name-only lookup is ambiguous whenever the lockfile carries
+    // more than one version of the same crate (an ordinary outcome of
+    // transitive diamonds), while `current_id`'s own resolved edges pin down
+    // exactly the version this manifest's dependency requirement locked to.
+    fn resolved_package_id(
+        resolve: &Resolve,
+        current_id: PackageId,
+        dep: &CargoDependency,
+    ) -> Fallible<PackageId> {
+        resolve
+            .deps(current_id)
+            .find(|(_, deps)| {
+                deps.iter()
+                    .any(|d| d.package_name() == dep.package_name() && d.kind() == dep.kind())
+            })
+            .map(|(package_id, _)| package_id)
+            .ok_or_else(|| {
+                format_err!(
+                    "`{}` is not present in the resolved dependency graph; run `cargo build` first",
+                    dep.package_name()
+                )
+            })
+    }
+
+    pub fn parse(
+        resolve: &Resolve,
+        current_id: PackageId,
+        deps_path: &Path,
+        dep: &CargoDependency,
+        artifacts: Option<&ArtifactMap>,
+    ) -> Fallible<Dependency> {
         if !deps_path.exists() {
             return Err(failure::err_msg("dependencies path is not exist."));
         }
 
         let package_name = dep.package_name().to_string();
+        let name_in_toml = dep.name_in_toml().to_string();
+        let package_id = Dependency::resolved_package_id(resolve, current_id, dep)?;
 
-        let locator = match dep.source_id().git_reference() {
-            Some(git_ref) => Dependency::parse_git(package_name.clone(), git_ref),
-            None => Dependency::parse_normal(package_name.clone(), dep.version_req().to_string()),
+        let locator = if dep.source_id().git_reference().is_some() {
+            Dependency::parse_git(package_id, package_name.clone(), name_in_toml.clone())
+        } else if dep.source_id().is_path() {
+            Dependency::parse_path(dep, package_id, package_name.clone(), name_in_toml.clone())
+        } else {
+            Dependency::parse_normal(package_id, package_name.clone(), name_in_toml.clone())
         }?;
 
         let crate_name = locator.crate_name();
-        let library_path = locator.find_library_path(deps_path)?;
+        let library_path = locator.find_library_path(deps_path, artifacts)?;
 
         Ok(Dependency {
             crate_name,
@@ -73,24 +256,60 @@ impl Dependency {
 
 enum Locator {
     Version {
+        package_id: PackageId,
         package_name: String,
+        name_in_toml: String,
         version: String,
     },
     Git {
+        package_id: PackageId,
         package_name: String,
+        name_in_toml: String,
         revision: String,
     },
+    Path {
+        package_id: PackageId,
+        package_name: String,
+        name_in_toml: String,
+        source_path: PathBuf,
+    },
 }
 
 impl Locator {
+    fn package_id(&self) -> PackageId {
+        match self {
+            Locator::Version { package_id, .. } => *package_id,
+            Locator::Git { package_id, .. } => *package_id,
+            Locator::Path { package_id, .. } => *package_id,
+        }
+    }
+
     fn package_name(&self) -> &str {
         match self {
             Locator::Version { package_name, .. } => package_name,
             Locator::Git { package_name, .. } => package_name,
+            Locator::Path { package_name, .. } => package_name,
         }
     }
 
+    fn name_in_toml(&self) -> &str {
+        match self {
+            Locator::Version { name_in_toml, .. } => name_in_toml,
+            Locator::Git { name_in_toml, .. } => name_in_toml,
+            Locator::Path { name_in_toml, .. } => name_in_toml,
+        }
+    }
+
+    // The name the dependency is imported under at the use site, which may
+    // differ from the package's own name when the manifest renames it
+    // (`foo = { package = "bar", ... }`).
     fn crate_name(&self) -> String {
+        self.name_in_toml().replace("-", "_")
+    }
+
+    // The name the crate was actually compiled under, used to locate its
+    // build artifacts on disk; unaffected by manifest renaming.
+    fn file_crate_name(&self) -> String {
         self.package_name().replace("-", "_")
     }
 
@@ -111,14 +330,21 @@ impl Locator {
             Locator::Version {
                 package_name,
                 version,
+                ..
             } => vec![format!("/{}-{}/", package_name, version)],
             Locator::Git {
                 package_name,
                 revision,
+                ..
             } => vec![
                 format!("/{}", package_name),
                 format!("/{}/", &revision[0..7]),
             ],
+            // Path dependencies carry no version/revision fragment in their
+            // `.d` file, so disambiguate candidates sharing a crate-name
+            // prefix by checking whether the file references the source
+            // directory instead.
+            Locator::Path { source_path, .. } => vec![source_path.display().to_string()],
         }
     }
 
@@ -128,8 +354,25 @@ impl Locator {
             .all(|pat| content.contains(pat))
     }
 
-    fn find_library_path(&self, deps_path: &Path) -> Fallible<PathBuf> {
-        let crate_name = self.crate_name();
+    fn find_library_path(
+        &self,
+        deps_path: &Path,
+        artifacts: Option<&ArtifactMap>,
+    ) -> Fallible<PathBuf> {
+        if let Some(artifacts) = artifacts {
+            let package_id = self.package_id();
+            return artifacts
+                .get(&package_key(package_id.name(), package_id.version()))
+                .cloned()
+                .ok_or_else(|| {
+                    format_err!(
+                        "`cargo build --message-format=json` produced no artifact for {}",
+                        self.package_name()
+                    )
+                });
+        }
+
+        let crate_name = self.file_crate_name();
         for file in deps_path.read_dir()? {
             let file = file?;
             if file.file_type()?.is_dir() {
@@ -185,6 +428,23 @@ struct Opt {
         help("Output format")
     )]
     format: OutputFormat,
+    #[structopt(
+        long,
+        value_name("KIND"),
+        default_value("normal"),
+        possible_values(&DepKind::variants()),
+        use_delimiter(true),
+        help("Dependency kinds to include (comma-separated)")
+    )]
+    dep_kinds: Vec<DepKind>,
+    #[structopt(
+        long,
+        value_name("RESOLVER"),
+        default_value("dot-files"),
+        possible_values(&Resolver::variants()),
+        help("Strategy used to locate compiled dependency artifacts (build-plan does not support --dep-kinds dev)")
+    )]
+    resolver: Resolver,
 }
 
 #[derive(EnumString, EnumVariantNames, Debug)]
@@ -194,12 +454,47 @@ enum OutputFormat {
     Json,
 }
 
+#[derive(EnumString, EnumVariantNames, Debug, Clone, Copy, PartialEq, Eq)]
+#[strum(serialize_all = "kebab_case")]
+enum DepKind {
+    Normal,
+    Dev,
+    Build,
+}
+
+impl DepKind {
+    fn matches(self, kind: CargoDepKind) -> bool {
+        match (self, kind) {
+            (DepKind::Normal, CargoDepKind::Normal) => true,
+            (DepKind::Dev, CargoDepKind::Development) => true,
+            (DepKind::Build, CargoDepKind::Build) => true,
+            _ => false,
+        }
+    }
+}
+
+#[derive(EnumString, EnumVariantNames, Debug, Clone, Copy, PartialEq, Eq)]
+#[strum(serialize_all = "kebab_case")]
+enum Resolver {
+    DotFiles,
+    BuildPlan,
+}
+
 fn run(
     Opt {
         manifest_path,
         format,
+        dep_kinds,
+        resolver,
     }: Opt,
 ) -> Fallible<()> {
+    if resolver == Resolver::BuildPlan && dep_kinds.contains(&DepKind::Dev) {
+        return Err(failure::err_msg(
+            "--resolver build-plan does not support --dep-kinds dev: `cargo build --release` \
+             does not compile dev-dependencies; use --resolver dot-files instead",
+        ));
+    }
+
     let config = Config::default()?;
 
     let manifest_path = manifest_path
@@ -209,11 +504,26 @@ fn run(
 
     let current = ws.current()?;
     let deps_path = ws.target_dir().join("release").join("deps");
+    let resolve = Dependency::load_resolve(&ws)?;
+
+    let artifacts = match resolver {
+        Resolver::DotFiles => None,
+        Resolver::BuildPlan => Some(build_artifact_map(&manifest_path)?),
+    };
 
     let mut options = current
         .dependencies()
         .iter()
-        .map(|dep| Dependency::parse(deps_path.as_path_unlocked(), dep))
+        .filter(|dep| dep_kinds.iter().any(|kind| kind.matches(dep.kind())))
+        .map(|dep| {
+            Dependency::parse(
+                &resolve,
+                current.package_id(),
+                deps_path.as_path_unlocked(),
+                dep,
+                artifacts.as_ref(),
+            )
+        })
         .collect::<Fallible<Vec<_>>>()?
         .into_iter()
         .flat_map(|dep| dep.make_compile_option())
@@ -240,3 +550,27 @@ fn main() {
         cargo::exit_with_error(err.into(), &mut Shell::new());
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cargo::core::SourceId;
+
+    #[test]
+    fn build_plan_artifact_key_matches_the_lookup_key_for_a_registry_dep() {
+        let config = Config::default().unwrap();
+        let source_id = SourceId::crates_io(&config).unwrap();
+        let package_id = PackageId::new("foo", "1.2.3", source_id).unwrap();
+
+        // What `cargo build --message-format=json` actually puts in a
+        // `compiler-artifact` message's `package_id` field for a crates.io
+        // dependency.
+        let message_package_id =
+            "foo 1.2.3 (registry+https://github.com/rust-lang/crates.io-index)";
+
+        let artifact_key = parse_artifact_key(message_package_id).unwrap();
+        let lookup_key = package_key(package_id.name(), package_id.version());
+
+        assert_eq!(artifact_key, lookup_key);
+    }
+}